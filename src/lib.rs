@@ -3,212 +3,147 @@
 #[macro_use]
 extern crate log;
 
-use std::ops::{Deref, Drop};
+pub mod sync;
+
+pub use sync::{SyncHashConser, SyncHashConsed};
+
+use std::ops::Deref;
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::collections::hash_map::{RandomState, DefaultHasher};
+use std::hash::{Hash, Hasher, BuildHasher};
 use std::fmt::{self, Debug};
-use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::{Arc, Weak};
+use std::cell::RefCell;
 
-
-/// Box that encapsulates a value to hash-cons, a reference to the conser,
-/// and a reference counter
-struct HashConsedBox<T>
-    where T: Eq + Hash
-{
+/// Interned value paired with its cached content fingerprint.
+///
+/// The fingerprint is computed once, when the value is first interned, and
+/// never changes afterwards.
+struct Node<T> {
     value: T,
-    conser: HashConser<T>,
-    refs: usize,
+    fingerprint: u128,
 }
 
-/// Unsafed reference to a hash-consed value
-///
-/// It is simply a pointer to the hash-consed box that encapsulate the raw value,
-/// a reference to the conser and the current number of references.
-///
-/// **N.B.:** An unsafed hash-consed value:
-///
-///   * may or may not be in the conser's map,
-///
-///   * should be `destroy()`-ed manually,
+/// Interning table, mapping the hash of a value to the weak references of the
+/// nodes sharing that hash.
 ///
-///   * does not update automatically the ref count,
+/// Only weak references are kept, so interned values are reclaimed as soon as
+/// their last [`HashConsed`] handle drops; the resulting dangling entries are
+/// purged lazily the next time their bucket is probed.
 ///
-///   * inherits PartialEq, Eq, and Hash from the raw value.
-struct UnsafeRef<T> where T: Eq + Hash {
-    ptr: *mut HashConsedBox<T>,
-    _marker: PhantomData<HashConsedBox<T>>,
-}
-
-impl<T> UnsafeRef<T> where T: Eq + Hash {
-
-    /// Make an unsafed reference to a owned hash-consed box
-    #[inline]
-    fn make(conser: &HashConser<T>, value: T) -> Self {
-        UnsafeRef {
-            ptr: Box::into_raw(Box::new(HashConsedBox {
-                value: value,
-                conser: conser.clone(),
-                refs: 0,
-            })),
-            _marker: PhantomData
-        }
-    }
-
-    /// Destroy (drop) the underlying hash-consed box
-    #[inline]
-    fn destroy(&self) {
-        drop(unsafe { Box::from_raw(self.ptr) });
-    }
-
-    /// Get pointer to conser
-    #[inline]
-    fn conser(&self) -> &mut HashConser<T> {
-        unsafe { &mut (*self.ptr).conser }
-    }
+/// Each entry carries its node's fingerprint so that, when a dangling weak is
+/// purged, the matching [`Index`] entry can be dropped in the same pass.
+type Table<T> = HashMap<u64, Vec<(u128, Weak<Node<T>>)>>;
 
-    #[inline]
-    fn refs(&self) -> usize {
-        unsafe { (*self.ptr).refs }
-    }
-
-    #[inline]
-    fn inc_refs(&self) {
-        unsafe { (*self.ptr).refs += 1; }
-    }
-
-    #[inline]
-    fn dec_refs(&self) {
-        unsafe { (*self.ptr).refs += 1; }
-    }
-
-    #[inline]
-    fn value(&self) -> &T {
-        unsafe { &(*self.ptr).value }
-    }
-
-}
-
-/// Hash the underlying value
-impl<T> Hash for UnsafeRef<T> where T: Eq + Hash {
-
-    #[inline]
-    fn hash<H>(&self, h: &mut H)
-        where H: Hasher
-    {
-        self.value().hash(h);
-    }
-
-}
-
-/// Compare the underlying values
-impl<T> PartialEq<UnsafeRef<T>> for UnsafeRef<T> where T: Eq + Hash {
-
-    #[inline]
-    fn eq(&self, other: &Self) -> bool {
-        self.value() == other.value()
-    }
+/// Fingerprint index, mapping a content fingerprint back to its node.
+type Index<T> = HashMap<u128, Weak<Node<T>>>;
 
+/// Compute the stable 128-bit content fingerprint of a value.
+///
+/// Two fixed-key 64-bit SipHashes (the standard `DefaultHasher`, seeded with
+/// distinct domain-separation tags) form the low and high halves of the
+/// digest. The result depends only on the value's `Hash`, never on its
+/// allocation address, so it is identical across program runs and suitable as
+/// a persistent key for serializing and relinking interned graphs.
+fn fingerprint_of<T: Hash>(value: &T) -> u128 {
+    let mut lo = DefaultHasher::new();
+    0u8.hash(&mut lo);
+    value.hash(&mut lo);
+    let mut hi = DefaultHasher::new();
+    0xffu8.hash(&mut hi);
+    value.hash(&mut hi);
+    ((hi.finish() as u128) << 64) | (lo.finish() as u128)
 }
 
-impl<T> Eq for UnsafeRef<T> where T: Eq + Hash {}
-
-impl<T> Clone for UnsafeRef<T> where T: Eq + Hash {
-
-    #[inline]
-    fn clone(&self) -> Self {
-        *self
-    }
-
+/// Integer finalizer for pointer-keyed hashing.
+///
+/// Heap pointers carry their entropy in the low and middle bits, which plays
+/// badly with SwissTable-style maps that derive their SIMD match tag from the
+/// *top* byte of the hash. Running the address through a wyhash/fxhash-style
+/// mix spreads that entropy across the whole word so the high byte is
+/// well-distributed.
+#[inline]
+pub(crate) fn mix_addr<T: ?Sized>(ptr: *const T) -> u64 {
+    let x = (ptr as *const () as usize) as u64;
+    let x = x.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x ^ (x >> 32)
 }
 
-impl<T> Copy for UnsafeRef<T> where T: Eq + Hash {}
-
 /// Reference to a hash-consed value.
 ///
-/// Built through a `HashConser`, it points to a single copy of the raw value existing in the
-/// `HashConser`.
+/// Built through a [`HashConser`], it points to the single copy of the raw
+/// value shared by every equal handle.
 ///
 /// Uses fast pointer equality and hash.
-pub struct HashConsed<T>(UnsafeRef<T>) where T: Eq + Hash;
+pub struct HashConsed<T>(Arc<Node<T>>) where T: Eq + Hash;
 
 impl<T> HashConsed<T> where T: Eq + Hash {
 
-    /// Wrap an unsafe reference
-    fn from_unsafe(u: &UnsafeRef<T>) -> Self {
-        u.inc_refs();
-        debug!("new ref {:p} ({} ref total)", u.value(), u.refs());
-        HashConsed(*u)
+    /// Raw address of the shared node, used for the pointer-based fast paths.
+    #[inline]
+    fn addr(&self) -> *const Node<T> {
+        Arc::as_ptr(&self.0)
     }
 
-
-    /// Get parent conser
-    pub fn conser(this: &Self) -> &HashConser<T> {
-        this.0.conser()
+    /// Stable 128-bit content fingerprint of the value.
+    ///
+    /// Unlike the address-based [`Hash`]/[`Eq`] impls, the fingerprint is
+    /// independent of the allocation and stable across runs, so it can key a
+    /// value when persisting a graph of hash-consed nodes to disk.
+    #[inline]
+    pub fn fingerprint(&self) -> u128 {
+        self.0.fingerprint
     }
+
 }
 
-/// Get reference to the raw value
+/// Get reference to the raw value.
 impl<T> Deref for HashConsed<T> where T: Eq + Hash {
 
     type Target = T;
 
     #[inline]
     fn deref(&self) -> &T {
-        return self.0.value();
+        &self.0.value
     }
 
 }
 
 /// Fast hash (pointer-based)
 ///
-/// Beware that it does not make any sense if the compared values where built through different
-/// `HashConser`.
+/// Beware that it does not make any sense if the compared values where built
+/// through different `HashConser`.
 impl<T> Hash for HashConsed<T> where T: Eq + Hash {
 
     #[inline]
     fn hash<H>(&self, h: &mut H)
         where H: Hasher
     {
-        self.0.ptr.hash(h);
+        h.write_u64(mix_addr(self.addr()));
     }
 
 }
 
 /// Fast comparison (pointer-based)
 ///
-/// Beware that it does not make any sense if the compared values where built through different
-/// `HashConser`
+/// Beware that it does not make any sense if the compared values where built
+/// through different `HashConser`
 impl<T> PartialEq<HashConsed<T>> for HashConsed<T> where T: Eq + Hash {
 
     #[inline]
     fn eq(&self, other: &Self) -> bool {
-        self.0.ptr == other.0.ptr
+        Arc::ptr_eq(&self.0, &other.0)
     }
 
 }
 
 impl<T> Eq for HashConsed<T> where T: Eq + Hash {}
 
-impl<T> Drop for HashConsed<T> where T: Eq + Hash {
-
-    fn drop(&mut self) {
-        self.0.dec_refs();
-        debug!("del ref {:p} ({} refs remaining)",
-               self.0.value(),
-               self.0.refs());
-        if self.0.refs() == 0 {
-            debug!("del val {:p}", self.0.value());
-            self.0.conser().remove(&self.0);
-            self.0.destroy();
-        }
-    }
-
-}
-
 impl<T> Debug for HashConsed<T> where T: Eq + Hash + Debug {
 
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        Debug::fmt(self.0.value(), fmt)
+        Debug::fmt(&self.0.value, fmt)
     }
 
 }
@@ -216,134 +151,232 @@ impl<T> Debug for HashConsed<T> where T: Eq + Hash + Debug {
 /// Get a new reference to this hash-consed value.
 impl<T> Clone for HashConsed<T> where T: Eq + Hash {
 
+    #[inline]
     fn clone(&self) -> Self {
-        self.0.inc_refs();
-        debug!("new ref {:p} (clone, {} refs total)",
-               self.0.value(),
-               self.0.refs());
-        HashConsed(self.0)
+        HashConsed(Arc::clone(&self.0))
     }
 
 }
 
-type HM<T> where T: Eq + Hash = HashMap<UnsafeRef<T>, UnsafeRef<T>>;
-
-struct HashConserBox<T> where T: Eq + Hash {
-    map: HM<T>,
-    refs: usize,
+/// Shared state of a hash-conser.
+struct HashConserBox<T, S> where T: Eq + Hash, S: BuildHasher {
+    table: RefCell<Table<T>>,
+    fingerprints: RefCell<Index<T>>,
+    hasher: S,
 }
 
 /// Hash-conser, i.e. hash-consed value factory and cache.
-pub struct HashConser<T> where T: Eq + Hash {
-    ptr: *mut HashConserBox<T>,
-    _marker: PhantomData<HashConserBox<T>>,
-}
+///
+/// The `S` parameter selects the `BuildHasher` used to key the interning
+/// table; it defaults to the standard SipHash `RandomState`. A faster hasher
+/// (`FxHash` or `ahash`, say) can be passed to
+/// [`with_hasher`](HashConser::with_hasher). Only the hasher is pluggable: the
+/// table itself is always a `std` `HashMap`.
+pub struct HashConser<T, S = RandomState>(Rc<HashConserBox<T, S>>)
+    where T: Eq + Hash, S: BuildHasher;
 
-impl<T> HashConser<T> where T: Eq + Hash {
+impl<T> HashConser<T, RandomState> where T: Eq + Hash {
 
     /// Create a hash-conser.
     pub fn new() -> Self {
-        HashConser {
-            ptr: Box::into_raw(Box::new(HashConserBox {
-                map: HashMap::new(),
-                refs: 1,
-            })),
-            _marker: PhantomData,
-        }
+        HashConser::with_hasher(RandomState::new())
     }
 
+}
+
+impl<T> Default for HashConser<T, RandomState> where T: Eq + Hash {
+
     #[inline]
-    fn map(&self) -> &mut HM<T> {
-        unsafe { &mut (*self.ptr).map }
+    fn default() -> Self {
+        HashConser::new()
     }
 
-    #[inline]
-    fn refs(&self) -> usize {
-        unsafe { (*self.ptr).refs }
+}
+
+impl<T, S> HashConser<T, S> where T: Eq + Hash, S: BuildHasher {
+
+    /// Create a hash-conser keying its table with `hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        HashConser(Rc::new(HashConserBox {
+            table: RefCell::new(HashMap::new()),
+            fingerprints: RefCell::new(HashMap::new()),
+            hasher,
+        }))
     }
 
+    /// Hash of a raw value through the conser's `BuildHasher`.
     #[inline]
-    fn inc_refs(&self) {
-        unsafe {
-            (*self.ptr).refs += 1;
-        }
+    fn hash(&self, value: &T) -> u64 {
+        self.0.hasher.hash_one(value)
     }
 
-    #[inline]
-    fn dec_refs(&self) {
-        unsafe {
-            (*self.ptr).refs -= 1;
+    /// Probe `bucket` for a live entry equal to `value`, dropping the dead
+    /// weak references met on the way. Returns the reused strong reference if
+    /// an equal value is still interned.
+    ///
+    /// The fingerprint index entry of each dead node is removed alongside its
+    /// weak reference, so the index stays as self-purging as the value table.
+    fn probe(
+        bucket: &mut Vec<(u128, Weak<Node<T>>)>,
+        value: &T,
+        fingerprints: &mut Index<T>,
+    ) -> Option<Arc<Node<T>>> {
+        let mut i = 0;
+        while i < bucket.len() {
+            match bucket[i].1.upgrade() {
+                Some(arc) => {
+                    if arc.value == *value {
+                        return Some(arc);
+                    }
+                    i += 1;
+                }
+                None => {
+                    fingerprints.remove(&bucket[i].0);
+                    bucket.swap_remove(i);
+                }
+            }
         }
+        None
     }
 
     /// Make a hash-consed value from an unwrapped value
-    pub fn make(&mut self, obj: T) -> HashConsed<T> {
-        debug!("h-cons  {:p} in {:p}", &obj, self);
-        let input = UnsafeRef::make(self, obj);
-        let safe = match self.map().get(&input) {
-            Some(output) => {
-                debug!("recycle {:p} (already {} refs)",
-                       output.value(),
-                       output.refs());
-                input.destroy();
-                HashConsed::from_unsafe(output)
-            }
-            None => {
-                debug!("new val {:p} in {:p}", input.value(), self);
-                self.map().insert(input.clone(), input);
-                HashConsed::from_unsafe(&input)
-            }
-        };
-        debug!("/h-cons");
-        safe
+    ///
+    /// The value is only moved into a fresh allocation when it turns out to be
+    /// genuinely new; a hit on the common dedup path reuses the existing
+    /// handle without allocating.
+    pub fn make(&self, obj: T) -> HashConsed<T> {
+        debug!("h-cons {:p}", &obj);
+        let hash = self.hash(&obj);
+        let mut table = self.0.table.borrow_mut();
+        let mut fingerprints = self.0.fingerprints.borrow_mut();
+        let bucket = table.entry(hash).or_default();
+
+        if let Some(arc) = Self::probe(bucket, &obj, &mut fingerprints) {
+            debug!("recycle {:p}", Arc::as_ptr(&arc));
+            return HashConsed(arc);
+        }
+
+        let fingerprint = fingerprint_of(&obj);
+        let arc = Arc::new(Node { value: obj, fingerprint });
+        debug!("new val {:p}", Arc::as_ptr(&arc));
+        bucket.push((fingerprint, Arc::downgrade(&arc)));
+        fingerprints.insert(fingerprint, Arc::downgrade(&arc));
+        HashConsed(arc)
     }
 
-    #[inline]
-    fn remove(&mut self, hc: &UnsafeRef<T>) {
-        self.map().remove(hc);
+    /// Query interning without consuming the candidate value.
+    ///
+    /// Returns the existing handle when an equal value is already interned,
+    /// and `None` otherwise; nothing is allocated or inserted in either case.
+    pub fn make_ref(&self, value: &T) -> Option<HashConsed<T>> {
+        let hash = self.hash(value);
+        let mut table = self.0.table.borrow_mut();
+        let mut fingerprints = self.0.fingerprints.borrow_mut();
+        match table.get_mut(&hash) {
+            Some(bucket) => Self::probe(bucket, value, &mut fingerprints).map(HashConsed),
+            None => None,
+        }
     }
 
-}
+    /// Evict interned entries for which `keep` returns `false`.
+    ///
+    /// `keep` is handed each live value together with its current external
+    /// reference count, i.e. the number of outstanding [`HashConsed`] handles.
+    /// Entries whose value has already been dropped carry a zero count and are
+    /// evicted without calling `keep`. The table is walked once and entries are
+    /// removed in place; the number of evicted entries is returned.
+    ///
+    /// # Caveat
+    ///
+    /// Returning `false` for a value that still has live handles evicts it from
+    /// the table while those handles persist. The hash-consing invariant then
+    /// no longer holds for that value: a later [`make`](Self::make) of an equal
+    /// value allocates a fresh node, so the new handle is *not*
+    /// [`ptr_eq`](Arc::ptr_eq) with the surviving ones. Pass
+    /// [`sweep_unreferenced`](Self::sweep_unreferenced), or a predicate that
+    /// only drops the zero-refcount entries, to keep the invariant intact.
+    pub fn retain<F>(&self, mut keep: F) -> usize
+        where F: FnMut(&T, usize) -> bool
+    {
+        let mut table = self.0.table.borrow_mut();
+        let mut fingerprints = self.0.fingerprints.borrow_mut();
+        let mut evicted = 0;
+        table.retain(|_, bucket| {
+            let mut i = 0;
+            while i < bucket.len() {
+                let drop = match bucket[i].1.upgrade() {
+                    // `strong_count` counts the transient `arc` borrowed here
+                    // too, so discount it to recover the external handle count.
+                    Some(arc) => {
+                        if keep(&arc.value, Arc::strong_count(&arc) - 1) {
+                            false
+                        } else {
+                            fingerprints.remove(&arc.fingerprint);
+                            true
+                        }
+                    }
+                    None => true,
+                };
+                if drop {
+                    bucket.swap_remove(i);
+                    evicted += 1;
+                } else {
+                    i += 1;
+                }
+            }
+            !bucket.is_empty()
+        });
+        fingerprints.retain(|_, weak| weak.strong_count() > 0);
+        evicted
+    }
 
-impl<T> Clone for HashConser<T> where T: Eq + Hash {
+    /// Evict every entry whose external reference count has fallen to zero.
+    ///
+    /// A convenience wrapper over [`retain`](Self::retain) that purges the
+    /// values no longer referenced outside the conser. Returns the number of
+    /// evicted entries.
+    pub fn sweep_unreferenced(&self) -> usize {
+        self.retain(|_, refs| refs > 0)
+    }
 
-    #[inline]
-    fn clone(&self) -> Self {
-        self.inc_refs();
-        HashConser {
-            ptr: self.ptr,
-            _marker: PhantomData,
-        }
+    /// Look up a still-interned value by its content fingerprint.
+    ///
+    /// Returns a handle to the value whose [`fingerprint`](HashConsed::fingerprint)
+    /// equals `fingerprint`, or `None` if no such value is currently interned.
+    /// Lets a deserializer rehydrate references to a previously persisted graph
+    /// of hash-consed nodes.
+    pub fn get_by_fingerprint(&self, fingerprint: u128) -> Option<HashConsed<T>> {
+        self.0.fingerprints.borrow().get(&fingerprint)
+            .and_then(Weak::upgrade)
+            .map(HashConsed)
     }
 
 }
 
-impl<T> Drop for HashConser<T> where T: Eq + Hash {
-
-    fn drop(&mut self) {
-        self.dec_refs();
-        debug!("del ref HashConser({:p}) ({} refs remaining)",
-               self.ptr,
-               self.refs());
-        if self.refs() == 0 {
-            assert!(self.map().len() == 0);
-            debug!("del val HashConser({:p})", self.ptr);
-            let b = unsafe { Box::from_raw(self.ptr) };
-            drop(b);
-        }
+impl<T, S> Clone for HashConser<T, S> where T: Eq + Hash, S: BuildHasher {
+
+    #[inline]
+    fn clone(&self) -> Self {
+        HashConser(Rc::clone(&self.0))
     }
 
 }
 
-impl<T> Debug for HashConser<T> where T: Eq + Hash + Debug {
+impl<T, S> Debug for HashConser<T, S> where T: Eq + Hash + Debug, S: BuildHasher {
 
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        try!(fmt.write_str("{"));
-        for (i, k) in self.map().keys().enumerate() {
-            if i != 0 {
-                try!(fmt.write_str(", "));
+        fmt.write_str("{")?;
+        let mut first = true;
+        for bucket in self.0.table.borrow().values() {
+            for (_, weak) in bucket {
+                if let Some(arc) = weak.upgrade() {
+                    if !first {
+                        fmt.write_str(", ")?;
+                    }
+                    first = false;
+                    write!(fmt, "{:?} ({})", &arc.value, Arc::strong_count(&arc))?;
+                }
             }
-            try!(write!(fmt, "{:?} ({})", k.value(), k.refs()));
         }
         fmt.write_str("}")
     }
@@ -361,16 +394,66 @@ mod test {
 
     #[test]
     fn test_equality() {
-        let mut conser = HashConser::new();
+        let conser = HashConser::new();
         let a: HCPair = conser.make(Pair(0,1));
         let b: HCPair = conser.make(Pair(0,1));
         assert_eq!(a, b);
         assert_eq!(&*a as *const Pair, &*b as *const Pair);
     }
 
+    #[test]
+    fn test_make_ref() {
+        let conser = HashConser::new();
+        assert!(conser.make_ref(&Pair(0,1)).is_none());
+        let a: HCPair = conser.make(Pair(0,1));
+        let b = conser.make_ref(&Pair(0,1)).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_sweep_unreferenced() {
+        let conser = HashConser::new();
+        let _kept: HCPair = conser.make(Pair(0,1));
+        drop(conser.make(Pair(2,3)));
+        assert_eq!(conser.sweep_unreferenced(), 1);
+        assert!(conser.make_ref(&Pair(0,1)).is_some());
+        assert!(conser.make_ref(&Pair(2,3)).is_none());
+    }
+
+    #[test]
+    fn test_retain_predicate() {
+        let conser = HashConser::new();
+        let _a: HCPair = conser.make(Pair(0,1));
+        let _b: HCPair = conser.make(Pair(5,5));
+        let evicted = conser.retain(|p, _refs| p.0 == p.1);
+        assert_eq!(evicted, 1);
+        assert!(conser.make_ref(&Pair(0,1)).is_none());
+        assert!(conser.make_ref(&Pair(5,5)).is_some());
+    }
+
+    #[test]
+    fn test_fingerprint_stable_and_shared() {
+        let conser = HashConser::new();
+        let a: HCPair = conser.make(Pair(0,1));
+        let b: HCPair = conser.make(Pair(0,1));
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        let c: HCPair = conser.make(Pair(1,0));
+        assert!(a.fingerprint() != c.fingerprint());
+    }
+
+    #[test]
+    fn test_get_by_fingerprint() {
+        let conser = HashConser::new();
+        let a: HCPair = conser.make(Pair(7,8));
+        let fp = a.fingerprint();
+        assert_eq!(conser.get_by_fingerprint(fp).unwrap(), a);
+        drop(a);
+        assert!(conser.get_by_fingerprint(fp).is_none());
+    }
+
     #[test]
     fn test_drop_conser() {
-        let mut conser = HashConser::new();
+        let conser = HashConser::new();
         let a: HCPair = conser.make(Pair(0,1));
         let b: HCPair = conser.make(Pair(0,1));
         drop(conser);
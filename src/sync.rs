@@ -0,0 +1,251 @@
+//! Thread-safe hash-consing.
+//!
+//! Unlike the single-threaded [`HashConser`](../struct.HashConser.html), the
+//! conser and handles in this module can be freely shared across threads. The
+//! design follows the Hack `hcons` table: interned values live behind an
+//! `Arc<T>` and the table only retains a `Weak<T>`, sharded across a fixed
+//! number of buckets each guarded by its own lock so that interning from
+//! several threads contends on different shards.
+//!
+//! Reclamation is implicit: dropping the last strong `Arc` leaves a dangling
+//! `Weak` in its shard, which is purged lazily the next time a value with the
+//! same hash is interned. There is no drop-time callback into the conser and
+//! no `unsafe`.
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher, BuildHasher};
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Debug};
+use std::ops::Deref;
+use std::sync::{Arc, Weak, Mutex};
+
+/// Number of independently-locked shards.
+///
+/// Kept a power of two so that the shard index is a cheap mask of the value
+/// hash.
+const SHARDS: usize = 64;
+
+/// A shard of the interning table.
+///
+/// Weak references are grouped by the hash of their value; a collision on a
+/// given hash is the opportunity to drop the entries whose strong count has
+/// fallen to zero.
+type Shard<T> = HashMap<u64, Vec<Weak<T>>>;
+
+/// Thread-safe hash-consed value factory and cache.
+///
+/// Cloning a `SyncHashConser` shares the underlying table, so every clone
+/// interns into the same pool.
+pub struct SyncHashConser<T, S = RandomState>
+    where T: Eq + Hash, S: BuildHasher
+{
+    shards: Arc<Vec<Mutex<Shard<T>>>>,
+    hasher: S,
+}
+
+/// Reference to a thread-safe hash-consed value.
+///
+/// Points to the single `Arc<T>` shared by every handle of an equal value.
+/// Uses fast pointer equality and hash.
+pub struct SyncHashConsed<T>(Arc<T>) where T: Eq + Hash;
+
+impl<T> SyncHashConser<T, RandomState> where T: Eq + Hash {
+
+    /// Create a thread-safe hash-conser.
+    pub fn new() -> Self {
+        SyncHashConser::with_hasher(RandomState::new())
+    }
+
+}
+
+impl<T> Default for SyncHashConser<T, RandomState> where T: Eq + Hash {
+
+    #[inline]
+    fn default() -> Self {
+        SyncHashConser::new()
+    }
+
+}
+
+impl<T, S> SyncHashConser<T, S>
+    where T: Eq + Hash, S: BuildHasher
+{
+
+    /// Create a thread-safe hash-conser using `hasher` to key the table.
+    pub fn with_hasher(hasher: S) -> Self {
+        let mut shards = Vec::with_capacity(SHARDS);
+        for _ in 0..SHARDS {
+            shards.push(Mutex::new(HashMap::new()));
+        }
+        SyncHashConser {
+            shards: Arc::new(shards),
+            hasher,
+        }
+    }
+
+    /// Hash of a raw value through the conser's `BuildHasher`.
+    #[inline]
+    fn hash(&self, value: &T) -> u64 {
+        self.hasher.hash_one(value)
+    }
+
+    /// Make a hash-consed value from an unwrapped value.
+    ///
+    /// Returns the existing handle if an equal value is already interned,
+    /// otherwise interns `value` and returns a handle to it.
+    pub fn make(&self, value: T) -> SyncHashConsed<T> {
+        let hash = self.hash(&value);
+        let mut shard = self.shards[(hash as usize) & (SHARDS - 1)]
+            .lock()
+            .unwrap();
+        let bucket = shard.entry(hash).or_default();
+
+        // A collision on this hash is where we pay the lazy reclamation: scan
+        // the bucket, reusing a live entry equal to `value` and dropping the
+        // dead ones encountered along the way.
+        let mut i = 0;
+        while i < bucket.len() {
+            match bucket[i].upgrade() {
+                Some(arc) => {
+                    if *arc == value {
+                        return SyncHashConsed(arc);
+                    }
+                    i += 1;
+                }
+                None => {
+                    bucket.swap_remove(i);
+                }
+            }
+        }
+
+        let arc = Arc::new(value);
+        bucket.push(Arc::downgrade(&arc));
+        SyncHashConsed(arc)
+    }
+
+}
+
+impl<T, S> Clone for SyncHashConser<T, S>
+    where T: Eq + Hash, S: BuildHasher + Clone
+{
+
+    #[inline]
+    fn clone(&self) -> Self {
+        SyncHashConser {
+            shards: self.shards.clone(),
+            hasher: self.hasher.clone(),
+        }
+    }
+
+}
+
+impl<T> SyncHashConsed<T> where T: Eq + Hash {
+
+    /// Raw address of the shared value, used for the pointer-based fast paths.
+    #[inline]
+    fn addr(&self) -> *const T {
+        Arc::as_ptr(&self.0)
+    }
+
+}
+
+/// Get reference to the raw value.
+impl<T> Deref for SyncHashConsed<T> where T: Eq + Hash {
+
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+
+}
+
+/// Fast hash (pointer-based).
+///
+/// Beware that it does not make any sense if the compared values were built
+/// through different consers.
+impl<T> Hash for SyncHashConsed<T> where T: Eq + Hash {
+
+    #[inline]
+    fn hash<H>(&self, h: &mut H)
+        where H: Hasher
+    {
+        h.write_u64(super::mix_addr(self.addr()));
+    }
+
+}
+
+/// Fast comparison (pointer-based).
+///
+/// Beware that it does not make any sense if the compared values were built
+/// through different consers.
+impl<T> PartialEq<SyncHashConsed<T>> for SyncHashConsed<T> where T: Eq + Hash {
+
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+}
+
+impl<T> Eq for SyncHashConsed<T> where T: Eq + Hash {}
+
+/// Get a new reference to this hash-consed value.
+impl<T> Clone for SyncHashConsed<T> where T: Eq + Hash {
+
+    #[inline]
+    fn clone(&self) -> Self {
+        SyncHashConsed(Arc::clone(&self.0))
+    }
+
+}
+
+impl<T> Debug for SyncHashConsed<T> where T: Eq + Hash + Debug {
+
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&*self.0, fmt)
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq, Hash)]
+    struct Pair(u8, u8);
+    type HCPair = SyncHashConsed<Pair>;
+
+    #[test]
+    fn test_equality() {
+        let conser = SyncHashConser::new();
+        let a: HCPair = conser.make(Pair(0, 1));
+        let b: HCPair = conser.make(Pair(0, 1));
+        assert_eq!(a, b);
+        assert_eq!(&*a as *const Pair, &*b as *const Pair);
+    }
+
+    #[test]
+    fn test_distinct() {
+        let conser = SyncHashConser::new();
+        let a: HCPair = conser.make(Pair(0, 1));
+        let b: HCPair = conser.make(Pair(1, 0));
+        assert!(a != b);
+    }
+
+    #[test]
+    fn test_reclamation() {
+        let conser = SyncHashConser::new();
+        let a = conser.make(Pair(2, 3));
+        let first = &*a as *const Pair;
+        drop(a);
+        // The weak entry is dangling; the next intern of an equal value purges
+        // it and allocates afresh (possibly, but not necessarily, reusing the
+        // address).
+        let b = conser.make(Pair(2, 3));
+        let _ = first;
+        let c = conser.make(Pair(2, 3));
+        assert_eq!(b, c);
+    }
+}